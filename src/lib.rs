@@ -2,107 +2,404 @@
 //!
 //! `floydrivest` is a small and extremely lightweight crate that provides
 //! an in-place implementation of the Floyd-Rivest algorithm.
-use std::cmp::{max, min, Ordering};
-/// Moves the n-th element of the given Vector in the n-th position
-/// by using the Floyd-Rivest algorithm with linear time complexity.
+//!
+//! The crate is `no_std` by default behaviour: everything it needs is
+//! comparisons and swaps. The `std` feature (on by default, for drop-in
+//! compatibility with existing users) pulls in `std::f64`'s transcendental
+//! functions for the large-range pivot-sampling estimate. Without it,
+//! enable the `libm` feature to get the same estimate from the `libm`
+//! crate instead, or use neither in a `no_std`/no-`libm` environment, in
+//! which case sampling falls back to a window of about `sqrt(n)` elements
+//! on either side of the target index.
+#![no_std]
+#[cfg(feature = "std")]
+extern crate std;
+
+use core::cmp::Ordering;
+/// Moves the n-th element of the given slice into the n-th position by
+/// using the Floyd-Rivest algorithm with linear time complexity, using
+/// `T`'s natural ordering. Returns the elements before `nth_el`, the
+/// element now correctly at `nth_el`, and the elements after it, mirroring
+/// `slice::select_nth_unstable`.
+///
+/// # Examples
+///
+/// ```
+/// let mut v = vec![10, 7, 9, 7, 2, 8, 8, 1, 9, 4]; // a vector of i64.
+/// let (before, nth, after) = floydrivest::nth_element(&mut v, 3);
+///
+/// assert_eq!(*nth, 7);
+/// assert!(before.iter().all(|x| x <= nth));
+/// assert!(after.iter().all(|x| x >= nth));
+/// ```
+///
+/// # Panics
+///
+/// if `nth_el` is out of bounds
+pub fn nth_element<T>(a: &mut [T], nth_el: usize) -> (&mut [T], &mut T, &mut [T])
+where
+    T: Ord,
+{
+    nth_element_by(a, nth_el, &mut Ord::cmp)
+}
+
+/// Same as [`nth_element`], but orders elements with a custom comparator
+/// instead of requiring `T: Ord`. Similar to its c++ counterpart and to
+/// `slice::select_nth_unstable_by`.
+///
+/// # Panics
+///
+/// if `nth_el` is out of bounds
+pub fn nth_element_by<'a, T, F>(
+    a: &'a mut [T],
+    nth_el: usize,
+    cmp: &mut F,
+) -> (&'a mut [T], &'a mut T, &'a mut [T])
+where
+    F: FnMut(&T, &T) -> Ordering,
+{
+    let max_iters = iteration_budget(a.len());
+    floydrivest(a, nth_el, 0, a.len() - 1, cmp, max_iters);
+    let (left, rest) = a.split_at_mut(nth_el);
+    let (mid, right) = rest.split_first_mut().expect("nth_el is in bounds");
+    (left, mid, right)
+}
+
+/// Same as [`nth_element`], but orders elements by the key that `f`
+/// extracts from them instead of by `T`'s own ordering. Mirrors
+/// `slice::select_nth_unstable_by_key`.
+///
+/// # Panics
 ///
-/// Similar to its c++ counterpart.
+/// if `nth_el` is out of bounds
+pub fn nth_element_by_key<T, K, F>(
+    a: &mut [T],
+    nth_el: usize,
+    mut f: F,
+) -> (&mut [T], &mut T, &mut [T])
+where
+    F: FnMut(&T) -> K,
+    K: Ord,
+{
+    nth_element_by(a, nth_el, &mut |x, y| f(x).cmp(&f(y)))
+}
+
+/// Places several order statistics at once: given a sorted,
+/// deduplicated list of target indices `ks`, reorders `a` so that every
+/// index in `ks` simultaneously holds the element that would be there if
+/// `a` were fully sorted, e.g. to compute several percentiles of a
+/// dataset with a single call.
 ///
+/// Partitioning work is shared across targets instead of running
+/// [`nth_element_by`] once per target, which costs `O(n log k)` rather
+/// than `O(k * n)`.
 ///
 /// # Examples
 ///
 /// ```
-/// let mut v = vec![10, 7, 9, 7, 2, 8, 8, 1, 9, 4]; // a vector of i64.
-/// floydrivest::nth_element(&mut v, 3, &mut Ord::cmp);
+/// let mut v = vec![10, 7, 9, 7, 2, 8, 8, 1, 9, 4];
+/// floydrivest::nth_elements(&mut v, &[0, 5, 9], &mut Ord::cmp);
 ///
-/// assert_eq!(v[3], 7);
+/// assert_eq!(v[0], 1);
+/// assert_eq!(v[5], 8);
+/// assert_eq!(v[9], 10);
 /// ```
 ///
 /// # Panics
 ///
-/// if `left`, `right` or `nth_el` are out of bounds
-pub fn nth_element<T, F>(a: &mut [T], nth_el: usize, cmp: &mut F)
+/// if any index in `ks` is out of bounds, or if `ks` is not sorted in
+/// ascending order
+pub fn nth_elements<T, F>(a: &mut [T], ks: &[usize], cmp: &mut F)
 where
     F: FnMut(&T, &T) -> Ordering,
-    T: Clone,
 {
-    floydrivest(a, nth_el, 0, a.len() - 1, cmp);
+    assert!(
+        ks.windows(2).all(|w| w[0] < w[1]),
+        "ks must be sorted and deduplicated"
+    );
+    assert!(
+        ks.last().map_or(true, |&k| k < a.len()),
+        "ks index out of bounds"
+    );
+    if a.is_empty() || ks.is_empty() {
+        return;
+    }
+    multi_select(a, ks, 0, a.len() - 1, cmp);
 }
 
-fn floydrivest<T, F>(a: &mut [T], nth_el: usize, mut left: usize, mut right: usize, cmp: &mut F)
+/// Recursive core of [`nth_elements`]: partitions `a[left..=right]` around
+/// the Floyd-Rivest-selected pivot landing at the middle target in `ks`,
+/// then recurses into whichever side still has pending targets.
+fn multi_select<T, F>(a: &mut [T], ks: &[usize], left: usize, right: usize, cmp: &mut F)
 where
     F: FnMut(&T, &T) -> Ordering,
-    T: Clone,
 {
-    let mut i: usize;
-    let mut j: usize;
-    let mut t: T;
+    if ks.is_empty() || left >= right {
+        return;
+    }
+    let mid = ks.len() / 2;
+    let p = ks[mid];
+    let max_iters = iteration_budget(right - left + 1);
+    floydrivest(a, p, left, right, cmp, max_iters);
+
+    let (lower, upper) = (&ks[..mid], &ks[mid + 1..]);
+    if p > left {
+        multi_select(a, lower, left, p - 1, cmp);
+    }
+    if p < right {
+        multi_select(a, upper, p + 1, right, cmp);
+    }
+}
+
+/// Forwards the transcendental functions the pivot-sampling estimate
+/// needs to whichever backend is available: `std::f64` by default, or
+/// `libm` for `no_std` callers that enable the `libm` feature.
+#[cfg(feature = "std")]
+mod float_ops {
+    pub(crate) fn ln(x: f64) -> f64 {
+        x.ln()
+    }
+    pub(crate) fn exp(x: f64) -> f64 {
+        x.exp()
+    }
+    pub(crate) fn sqrt(x: f64) -> f64 {
+        x.sqrt()
+    }
+}
+
+#[cfg(all(not(feature = "std"), feature = "libm"))]
+mod float_ops {
+    pub(crate) fn ln(x: f64) -> f64 {
+        libm::log(x)
+    }
+    pub(crate) fn exp(x: f64) -> f64 {
+        libm::exp(x)
+    }
+    pub(crate) fn sqrt(x: f64) -> f64 {
+        libm::sqrt(x)
+    }
+}
+
+/// Estimates a sample window `[ll, rr] ⊆ [left, right]` that is expected
+/// to contain the `(nth_el - left + 1)`-th smallest element, biased
+/// slightly so that element is expected to land in the smaller of the two
+/// partitions once sampling recurses. This is the Floyd-Rivest sampling
+/// formula.
+#[cfg(any(feature = "std", feature = "libm"))]
+fn sample_bounds(left: usize, right: usize, nth_el: usize) -> (usize, usize) {
+    let n: f64 = (right - left + 1) as f64;
+    let i: f64 = (nth_el - left + 1) as f64;
+    let z: f64 = float_ops::ln(n);
+    let s: f64 = 0.5 * float_ops::exp(z * (2.0 / 3.0));
+    let sn: f64 = s / n;
+    let sd: f64 = 0.5 * float_ops::sqrt(z * s * (1.0 - sn)) * (i - n * 0.5).signum();
+
+    let isn: f64 = i * s / n;
+    let inner: f64 = nth_el as f64 - isn + sd;
+    let ll: usize = left.max(inner as usize);
+    let rr: usize = right.min((inner + s) as usize);
+    (ll, rr)
+}
+
+/// Without `std` or `libm` there's no portable `ln`/`exp`/`sqrt` to
+/// compute the real Floyd-Rivest sample window, so fall back to a window
+/// reaching roughly `sqrt(n)` to either side of `nth_el`. It's a cruder estimate
+/// than the sampling formula above (which targets closer to `n^(2/3)`),
+/// but it keeps the sampling step cheap relative to the range it is
+/// narrowing, and `floydrivest`'s iteration-budget fallback to
+/// median-of-medians still bounds the worst case.
+#[cfg(not(any(feature = "std", feature = "libm")))]
+fn sample_bounds(left: usize, right: usize, nth_el: usize) -> (usize, usize) {
+    let window = (right - left + 1).isqrt().max(1);
+    (
+        nth_el.saturating_sub(window).max(left),
+        (nth_el + window).min(right),
+    )
+}
+
+/// Caps the number of sampled-pivot partitioning attempts `floydrivest` is
+/// allowed before it bails out into the deterministic median-of-medians
+/// fallback. Roughly `c * log2(n)`: generous enough that the fast path
+/// never trips on typical inputs, but tight enough to bound the worst case.
+fn iteration_budget(n: usize) -> usize {
+    let log2n = (usize::BITS - n.max(1).leading_zeros()) as usize;
+    4 * log2n.max(1) + 16
+}
+
+fn floydrivest<T, F>(
+    a: &mut [T],
+    nth_el: usize,
+    mut left: usize,
+    mut right: usize,
+    cmp: &mut F,
+    mut max_iters: usize,
+) where
+    F: FnMut(&T, &T) -> Ordering,
+{
     while right > left {
-        if right - left > 600 {
+        if max_iters == 0 {
+            // The sampled-pivot estimate has failed to shrink the active
+            // range quickly enough, which only happens on adversarial or
+            // highly structured inputs. Borrowed from pdqsort's
+            // pattern-defeating bailout: switch to a deterministic pivot
+            // chosen by median-of-medians (BFPRT). It is slower per step,
+            // but guarantees the partition shrinks by a constant fraction,
+            // which bounds the total work at O(n).
+            let pivot = median_of_medians(a, left, right, cmp);
+            a.swap(pivot, nth_el);
+        } else if right - left > 600 {
             // Use recursion on a sample of size s to get an estimate
             // for the (nth_el - left + 1 )-th smallest elementh into a[nth_el],
             // biased slightly so that the (nth_el - left + 1)-th element is expected
             // to lie in the smallest set after partitioning.
-            let n: f64 = (right - left + 1) as f64;
-            let i: f64 = (nth_el - left + 1) as f64;
-            let z: f64 = n.ln();
-            let s: f64 = 0.5 * (z * (2.0 / 3.0)).exp();
-            let sn: f64 = s / n;
-            let sd: f64 = 0.5 * (z * s * (1.0 - sn)).sqrt() * (i - n * 0.5).signum();
-
-            let isn: f64 = i * s / n;
-            let inner: f64 = nth_el as f64 - isn + sd;
-            let ll: usize = max(left, inner as usize);
-            let rr: usize = min(right, (inner + s) as usize);
-            floydrivest(a, nth_el, ll, rr, cmp);
+            let (ll, rr) = sample_bounds(left, right, nth_el);
+            floydrivest(a, nth_el, ll, rr, cmp, iteration_budget(rr - ll + 1));
         }
-        // The following code partitions a[l : r] about t, it is similar to Hoare's
-        // algorithm but it'll run faster on most machines since the subscript range
-        // checking on i and j has been removed.
-        t = a[nth_el].clone();
-        i = left;
-        j = right;
-        a.swap(left, nth_el);
-        if cmp(&a[right], &t) == Ordering::Greater {
-            a.swap(right, left);
+        max_iters = max_iters.saturating_sub(1);
+        // Partition a[left..=right] into three contiguous regions around
+        // the pivot at a[nth_el]: strictly less than it, equal to it, and
+        // strictly greater than it. Splitting out the equal region (rather
+        // than shuffling equal keys across the partition, as a plain
+        // two-way scheme does) keeps duplicate-heavy inputs from
+        // degrading towards quadratic behaviour. The pivot is tracked by
+        // index rather than cloned, so `T` only ever needs to support
+        // comparison and moves.
+        let (lt, gt) = three_way_partition(a, left, right, nth_el, cmp);
+        if nth_el >= lt && nth_el <= gt {
+            // nth_el fell inside the equal region, so it's already in
+            // its final sorted position.
+            return;
         }
-        while i < j {
-            a.swap(i, j);
-            i += 1;
-            j -= 1;
-            while cmp(&a[i], &t) == Ordering::Less {
+        if nth_el < lt {
+            right = lt - 1;
+        } else {
+            left = gt + 1;
+        }
+    }
+}
+
+/// Splits `a[left..=right]` into three contiguous regions around the pivot
+/// found at `pivot_idx`, using a Dutch-national-flag scan with three
+/// cursors (`lt`, `i`, `gt`): `i` advances across the range, swapping into
+/// `lt` on `Less` and into `gt` on `Greater`, while `Equal` elements are
+/// left in place and `i` simply moves past them. The pivot is followed by
+/// index as it gets shuffled by these swaps, rather than cloned out up
+/// front, so this works for any `T` the comparator can compare. Returns
+/// `(lt, gt)`, the inclusive bounds of the equal-to-pivot region.
+fn three_way_partition<T, F>(
+    a: &mut [T],
+    left: usize,
+    right: usize,
+    pivot_idx: usize,
+    cmp: &mut F,
+) -> (usize, usize)
+where
+    F: FnMut(&T, &T) -> Ordering,
+{
+    let mut lt = left;
+    let mut i = left;
+    let mut gt = right;
+    let mut p = pivot_idx;
+    while i <= gt {
+        match cmp(&a[i], &a[p]) {
+            Ordering::Less => {
+                a.swap(lt, i);
+                if p == lt {
+                    p = i;
+                } else if p == i {
+                    p = lt;
+                }
+                lt += 1;
                 i += 1;
             }
-            while cmp(&a[j], &t) == Ordering::Greater {
-                j -= 1;
+            Ordering::Greater => {
+                a.swap(i, gt);
+                if p == i {
+                    p = gt;
+                } else if p == gt {
+                    p = i;
+                }
+                match gt.checked_sub(1) {
+                    Some(g) => gt = g,
+                    None => break,
+                }
             }
+            Ordering::Equal => i += 1,
         }
-        if cmp(&a[left], &t) == Ordering::Equal {
-            a.swap(left, j);
-        } else {
-            j += 1;
-            a.swap(j, right);
+    }
+    (lt, gt)
+}
+
+/// Returns the index of a deterministic "good" pivot for `a[left..=right]`,
+/// computed by the median-of-medians (BFPRT) method: split the range into
+/// groups of (at most) 5, sort each group with insertion sort and collect
+/// its median into a contiguous prefix starting at `left`, then recurse on
+/// that prefix to find its true median. Because at least half the groups
+/// contribute two elements no greater (and two no smaller) than this
+/// median, the pivot it returns guarantees the partition shrinks the active
+/// range by a constant fraction.
+fn median_of_medians<T, F>(a: &mut [T], left: usize, right: usize, cmp: &mut F) -> usize
+where
+    F: FnMut(&T, &T) -> Ordering,
+{
+    let len = right - left + 1;
+    if len <= 5 {
+        insertion_sort(a, left, right, cmp);
+        return left + len / 2;
+    }
+
+    let mut groups = 0;
+    let mut lo = left;
+    loop {
+        let hi = (lo + 4).min(right);
+        insertion_sort(a, lo, hi, cmp);
+        a.swap(left + groups, lo + (hi - lo) / 2);
+        groups += 1;
+        if hi == right {
+            break;
         }
-        // Now we adjust left and right so that they
-        // surround the subset containing the
-        // (k - left + 1)-th smallest element.
-        if j <= nth_el {
-            left = j + 1;
-            if nth_el <= j {
-                right = j.saturating_sub(1);
-            }
+        lo += 5;
+    }
+
+    let mid = left + groups / 2;
+    floydrivest(
+        a,
+        mid,
+        left,
+        left + groups - 1,
+        cmp,
+        iteration_budget(groups),
+    );
+    mid
+}
+
+/// Sorts `a[left..=right]` in place; only ever called on the small (≤ 5
+/// element) groups used by `median_of_medians`, where its O(n²) behaviour
+/// is faster in practice than a general-purpose sort.
+fn insertion_sort<T, F>(a: &mut [T], left: usize, right: usize, cmp: &mut F)
+where
+    F: FnMut(&T, &T) -> Ordering,
+{
+    for i in (left + 1)..=right {
+        let mut j = i;
+        while j > left && cmp(&a[j], &a[j - 1]) == Ordering::Less {
+            a.swap(j, j - 1);
+            j -= 1;
         }
     }
 }
 
 #[cfg(test)]
+#[cfg(feature = "std")]
 #[cfg(not(tarpaulin_include))]
 mod tests {
-    use super::nth_element;
+    use super::{nth_element, nth_elements};
+    use std::vec;
+    use std::vec::Vec;
     #[test]
     fn test_simple() {
         let mut v = vec![10, 7, 9, 7, 2, 8, 8, 1, 9, 4];
-        nth_element(&mut v, 3, &mut Ord::cmp);
+        nth_element(&mut v, 3);
         assert_eq!(v[3], 7);
     }
     #[test]
@@ -110,7 +407,7 @@ mod tests {
     fn test_iter() {
         let mut v = vec![9, 5, 0, 6, 8, 2, 3, 7, 1, 4];
         for n in 0..10 {
-            nth_element(&mut v, n, &mut Ord::cmp);
+            nth_element(&mut v, n);
             assert_eq!(v[n], n);
         }
     }
@@ -192,8 +489,114 @@ mod tests {
             677, 182, 992, 245, 1041, 594, 200, 1014, 484, 1013, 1035, 715, 1033, 1029, 643, 529,
         ];
         for n in 0..(v.len() - 1) {
-            nth_element(&mut v, n, &mut Ord::cmp);
+            nth_element(&mut v, n);
             assert_eq!(v[n], n as u64);
         }
     }
+    #[test]
+    #[cfg(not(tarpaulin_include))]
+    fn organ_pipe_trips_median_of_medians_fallback() {
+        // A zigzag built from two interleaved runs (ascending on even
+        // indices, descending on odd ones) is a classic adversary for
+        // sampled-pivot selection: every sample drawn from the high or the
+        // low side keeps landing near one end, so the sampled-pivot
+        // estimate keeps thrashing and this drives `floydrivest` into its
+        // `max_iters == 0` median-of-medians fallback well before the
+        // range narrows on its own.
+        let n = 50_000usize;
+        let mut v: Vec<i64> = Vec::with_capacity(n);
+        for i in 0..n {
+            if i % 2 == 0 {
+                v.push((i / 2) as i64);
+            } else {
+                v.push((n - i / 2) as i64);
+            }
+        }
+        let mut sorted = v.clone();
+        sorted.sort();
+        for &k in &[0, n / 4, n / 2, n - 1] {
+            let mut vv = v.clone();
+            nth_element(&mut vv, k);
+            assert_eq!(vv[k], sorted[k]);
+        }
+    }
+    #[test]
+    #[cfg(not(tarpaulin_include))]
+    fn duplicate_heavy_partition() {
+        // `three_way_partition` exists for exactly this shape: a slice
+        // dominated by one repeated key, with a few distinct values
+        // scattered through it. A two-way partition would keep shuffling
+        // the equal keys across the split on every pass; the equal-region
+        // early return is what keeps this linear.
+        let n = 100_000usize;
+        let mut v = vec![5i64; n];
+        v[0] = 9;
+        v[1] = 1;
+        v[n / 3] = 7;
+        v[n / 2] = 2;
+        v[n - 1] = 8;
+        let mut sorted = v.clone();
+        sorted.sort();
+        for &k in &[0, 1, n / 2, n - 2, n - 1] {
+            let mut vv = v.clone();
+            nth_element(&mut vv, k);
+            assert_eq!(vv[k], sorted[k]);
+            assert!(vv[..k].iter().all(|x| *x <= vv[k]));
+            assert!(vv[k + 1..].iter().all(|x| *x >= vv[k]));
+        }
+    }
+    #[test]
+    #[cfg(not(tarpaulin_include))]
+    fn nth_elements_matches_sorted_oracle() {
+        // A small xorshift PRNG, seeded for reproducibility, stands in for
+        // `rand` so this stays a randomized test without pulling in a
+        // dependency. Values are taken modulo a small range to produce a
+        // duplicate-heavy input, exercising `multi_select`'s pivot-sharing
+        // recursion against several simultaneous, unevenly-spaced targets.
+        let mut state: u64 = 0x2545F4914F6CDD1D;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let n = 2_000usize;
+        let v: Vec<i64> = (0..n).map(|_| (next() % 50) as i64).collect();
+        let ks = [0usize, 1, 7, 42, n / 3, n / 2, n - n / 3, n - 2, n - 1];
+
+        let mut sorted = v.clone();
+        sorted.sort();
+
+        let mut vv = v.clone();
+        nth_elements(&mut vv, &ks, &mut Ord::cmp);
+        for &k in &ks {
+            assert_eq!(vv[k], sorted[k]);
+        }
+    }
+    #[test]
+    #[cfg(not(tarpaulin_include))]
+    fn by_and_by_key_split_around_nth() {
+        // Checks the actual contents of the returned `before`/`after`
+        // partitions, not just the element landing at `nth_el`: every
+        // element before it in the sign-insensitive comparator's ordering
+        // must compare `<=`, and every element after it must compare `>=`.
+        let mut v = vec![-10, 7, -9, 7, -2, 8, -8, 1, -9, 4];
+        let len = v.len();
+        let k = 4;
+        let (before, nth, after) = super::nth_element_by(&mut v, k, &mut |x: &i64, y: &i64| {
+            x.abs().cmp(&y.abs())
+        });
+        assert_eq!(before.len(), k);
+        assert_eq!(after.len(), len - k - 1);
+        assert!(before.iter().all(|x| x.abs() <= nth.abs()));
+        assert!(after.iter().all(|x| x.abs() >= nth.abs()));
+
+        let mut w = vec![-10, 7, -9, 7, -2, 8, -8, 1, -9, 4];
+        let len = w.len();
+        let (before, nth, after) = super::nth_element_by_key(&mut w, k, |x: &i64| x.abs());
+        assert_eq!(before.len(), k);
+        assert_eq!(after.len(), len - k - 1);
+        assert!(before.iter().all(|x| x.abs() <= nth.abs()));
+        assert!(after.iter().all(|x| x.abs() >= nth.abs()));
+    }
 }